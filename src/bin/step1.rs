@@ -1,18 +1,21 @@
 #![feature(exit_status)]
 
-extern crate lines;
 extern crate lisp;
+extern crate rustyline;
 
 use std::env;
-use std::io::{StdoutLock, Write, self};
+use std::io::{self, Write};
 
-use lines::Lines;
 use lisp::diagnostics;
 use lisp::syntax::ast::Expr;
 use lisp::syntax::codemap::Source;
 use lisp::syntax::pp;
 use lisp::syntax::{Error, parse};
 use lisp::util::interner::Interner;
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+const HISTORY_FILE: &'static str = ".lisp_history";
 
 fn read(source: &Source, interner: &mut Interner) -> Result<Expr, Error> {
     parse::expr(source, interner)
@@ -22,47 +25,93 @@ fn eval(input: Expr) -> Expr {
     input
 }
 
-fn print(output: &Expr, source: &Source, stdout: &mut StdoutLock) -> io::Result<()> {
-    let mut string = pp::expr(output, source);
-    string.push('\n');
-    stdout.write_all(string.as_bytes())
+fn print(output: &Expr, source: &Source) {
+    println!("{}", pp::expr(output, source));
 }
 
-fn rep(stdout: &mut StdoutLock) -> io::Result<()> {
-    const PROMPT: &'static str = "> ";
-
-    let stdin = io::stdin();
-    let mut lines = Lines::from(stdin.lock());
+/// Whether `buffer` has at least as many closing `(`/`[` as opening ones,
+/// ignoring delimiters that appear inside a string literal and `"`
+/// escaped with a backslash
+fn is_balanced(buffer: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
 
-    let ref mut interner = Interner::new();
-
-    try!(stdout.write_all(PROMPT.as_bytes()));
-    try!(stdout.flush());
-    while let Some(line) = lines.next() {
-        let source = Source::new(try!(line));
-
-        if !source.as_str().trim().is_empty() {
-            match read(source, interner) {
-                Err(error) => {
-                    try!(stdout.write_all(diagnostics::syntax(error, source).as_bytes()))
-                },
-                Ok(expr) => try!(print(&eval(expr), source, stdout)),
+    for c in buffer.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
             }
+
+            continue
         }
 
-        try!(stdout.write_all(PROMPT.as_bytes()));
-        try!(stdout.flush());
+        match c {
+            '"' => in_string = true,
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => {},
+        }
     }
 
-    Ok(())
+    depth <= 0
+}
+
+fn rep(editor: &mut Editor<()>) -> io::Result<()> {
+    const PROMPT: &'static str = "\x1b[1;32m>\x1b[0m ";
+    const CONTINUATION_PROMPT: &'static str = "\x1b[1;32m.\x1b[0m ";
+
+    let ref mut interner = Interner::new();
+    let mut buffer = String::new();
+
+    loop {
+        let prompt = if buffer.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !buffer.is_empty() {
+                    buffer.push('\n');
+                }
+                buffer.push_str(&line);
+
+                if !is_balanced(&buffer) {
+                    continue
+                }
+
+                if !buffer.trim().is_empty() {
+                    editor.add_history_entry(&buffer);
+
+                    let source = Source::new(buffer.clone());
+
+                    match read(source, interner) {
+                        Err(error) => {
+                            try!(io::stdout().write_all(diagnostics::syntax(error, source).as_bytes()))
+                        },
+                        Ok(expr) => print(&eval(expr), source),
+                    }
+                }
+
+                buffer.clear();
+            },
+            Err(ReadlineError::Interrupted) => buffer.clear(),
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
 }
 
 fn main() {
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
+    let mut editor = Editor::<()>::new();
+    editor.load_history(HISTORY_FILE).ok();
 
-    if let Err(e) = rep(&mut stdout) {
+    if let Err(e) = rep(&mut editor) {
         env::set_exit_status(1);
-        stdout.write_fmt(format_args!("{}", e)).ok();
+        writeln!(&mut io::stderr(), "{}", e).ok();
     }
+
+    editor.save_history(HISTORY_FILE).ok();
 }
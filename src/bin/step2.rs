@@ -1,70 +1,125 @@
 #![feature(exit_status)]
 
-extern crate lines;
 extern crate lisp;
+extern crate rustyline;
 
 use std::env;
-use std::io::{StdoutLock, Write, self};
+use std::io::{self, Write};
 
-use lines::Lines;
 use lisp::diagnostics;
 use lisp::eval::{Value, self};
-use lisp::eval::env::Env;
+use lisp::eval::env::Stack;
 use lisp::syntax::ast::Expr;
 use lisp::syntax::codemap::Source;
 use lisp::syntax::{parse, self};
+use lisp::util::interner::Interner;
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+const HISTORY_FILE: &'static str = ".lisp_history";
 
 fn read(source: &Source) -> Result<Expr, syntax::Error> {
     parse::expr(source)
 }
 
-fn eval(input: &Expr, source: &Source, env: &mut Env) -> Result<Value, eval::Error> {
+fn eval(input: &Expr, source: &Source, env: &mut Stack) -> Result<Value, eval::Error> {
     eval::expr(input, source, env)
 }
 
-fn print(value: &Value, stdout: &mut StdoutLock) -> io::Result<()> {
-    writeln!(stdout, "{}", value)
+fn print(value: &Value) {
+    println!("{}", value);
 }
 
-fn rep(stdout: &mut StdoutLock) -> io::Result<()> {
-    const PROMPT: &'static str = "> ";
-
-    let stdin = io::stdin();
-    let mut lines = Lines::from(stdin.lock());
-    let mut env = Env::default();
-
-    try!(stdout.write_all(PROMPT.as_bytes()));
-    try!(stdout.flush());
-    while let Some(line) = lines.next() {
-        let source = Source::new(try!(line));
-
-        if !source.as_str().trim().is_empty() {
-            match read(source) {
-                Err(error) => {
-                    try!(stdout.write_all(diagnostics::syntax(error, source).as_bytes()))
-                },
-                Ok(expr) => match eval(&expr, source, &mut env) {
-                    Err(error) => {
-                        try!(stdout.write_all(diagnostics::eval(error, source).as_bytes()))
-                    },
-                    Ok(value) => try!(print(&value, stdout)),
-                },
+/// Whether `buffer` has at least as many closing `(`/`[` as opening ones,
+/// ignoring delimiters that appear inside a string literal and `"`
+/// escaped with a backslash
+fn is_balanced(buffer: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in buffer.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
             }
+
+            continue
         }
 
-        try!(stdout.write_all(PROMPT.as_bytes()));
-        try!(stdout.flush());
+        match c {
+            '"' => in_string = true,
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => {},
+        }
     }
 
-    Ok(())
+    depth <= 0
+}
+
+fn rep(editor: &mut Editor<()>) -> io::Result<()> {
+    const PROMPT: &'static str = "\x1b[1;32m>\x1b[0m ";
+    const CONTINUATION_PROMPT: &'static str = "\x1b[1;32m.\x1b[0m ";
+
+    let mut env = Stack::default();
+    let mut buffer = String::new();
+    let ref mut interner = Interner::new();
+
+    loop {
+        let prompt = if buffer.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !buffer.is_empty() {
+                    buffer.push('\n');
+                }
+                buffer.push_str(&line);
+
+                if !is_balanced(&buffer) {
+                    continue
+                }
+
+                if !buffer.trim().is_empty() {
+                    editor.add_history_entry(&buffer);
+
+                    let source = Source::new(buffer.clone());
+
+                    match read(source) {
+                        Err(error) => {
+                            try!(io::stdout().write_all(diagnostics::syntax(error, source).as_bytes()))
+                        },
+                        Ok(expr) => match eval(&expr, source, &mut env) {
+                            Err(error) => {
+                                let rendered = diagnostics::eval(error, source, interner);
+                                try!(io::stdout().write_all(rendered.as_bytes()))
+                            },
+                            Ok(value) => print(&value),
+                        },
+                    }
+                }
+
+                buffer.clear();
+            },
+            Err(ReadlineError::Interrupted) => buffer.clear(),
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
 }
 
 fn main() {
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
+    let mut editor = Editor::<()>::new();
+    editor.load_history(HISTORY_FILE).ok();
 
-    if let Err(e) = rep(&mut stdout) {
+    if let Err(e) = rep(&mut editor) {
         env::set_exit_status(1);
-        writeln!(&mut stdout, "{}", e).ok();
+        writeln!(&mut io::stderr(), "{}", e).ok();
     }
+
+    editor.save_history(HISTORY_FILE).ok();
 }
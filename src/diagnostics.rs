@@ -0,0 +1,124 @@
+//! Human-friendly rendering of syntax and evaluation errors
+
+use eval::{Error, Error_};
+use syntax::codemap::Source;
+use util::interner::{Interner, Name};
+
+/// Renders a syntax error as a caret-annotated snippet of the offending
+/// source
+pub fn syntax(error: ::syntax::Error, source: &Source) -> String {
+    render(source.as_str(), error.span.lo, error.span.hi, &format!("{:?}", error.node), None)
+}
+
+/// Renders an evaluation error as a caret-annotated snippet of the
+/// offending source, plus a tailored help note for the errors that carry
+/// enough context for one
+pub fn eval(error: Error, source: &Source, interner: &Interner) -> String {
+    let message = message(&error.node, interner);
+    let help = help(&error.node, interner);
+
+    render(
+        source.as_str(),
+        error.span.lo,
+        error.span.hi,
+        &message,
+        help.as_ref().map(String::as_str),
+    )
+}
+
+fn message(error: &Error_, interner: &Interner) -> String {
+    match *error {
+        Error_::EmptyList => "an empty list is not callable".into(),
+        Error_::ExpectedFunction { found } => format!("expected a function, found a {}", found),
+        Error_::ExpectedSymbol => "expected a symbol".into(),
+        Error_::UndefinedSymbol { name, .. } => {
+            format!("undefined symbol `{}`", interner.get(&name))
+        },
+        Error_::UnsupportedOperation => "unsupported operation".into(),
+        Error_::WrongArity { expected, found } => {
+            format!("expected {} argument(s), found {}", expected, found)
+        },
+    }
+}
+
+fn help(error: &Error_, interner: &Interner) -> Option<String> {
+    match *error {
+        Error_::UndefinedSymbol { ref name, ref candidates } => {
+            closest(name, candidates, interner).map(|suggestion| {
+                format!("help: did you mean `{}`?", interner.get(&suggestion))
+            })
+        },
+        Error_::ExpectedFunction { found } => {
+            Some(format!("help: `{}` values can't be called like a function", found))
+        },
+        _ => None,
+    }
+}
+
+/// The candidate closest to `name` within an edit distance of 2, if any
+fn closest(name: &Name, candidates: &[Name], interner: &Interner) -> Option<Name> {
+    let target = interner.get(name);
+
+    candidates.iter()
+        .cloned()
+        .map(|candidate| {
+            let distance = levenshtein(&target, &interner.get(&candidate));
+            (candidate, distance)
+        })
+        .filter(|&(_, distance)| distance <= 2)
+        .min_by_key(|&(_, distance)| distance)
+        .map(|(candidate, _)| candidate)
+}
+
+/// Levenshtein edit distance between two strings
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+
+    for i in 1..a.len() + 1 {
+        let mut diagonal = row[0];
+        row[0] = i;
+
+        for j in 1..b.len() + 1 {
+            let above = row[j];
+
+            row[j] = if a[i - 1] == b[j - 1] {
+                diagonal
+            } else {
+                1 + diagonal.min(row[j]).min(row[j - 1])
+            };
+
+            diagonal = above;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Renders the source line containing the byte range `[start, end)` with a
+/// caret/underline beneath it, followed by `message` and an optional help
+/// note
+fn render(text: &str, start: usize, end: usize, message: &str, help: Option<&str>) -> String {
+    use std::iter::repeat;
+
+    let line_start = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = text[start..].find('\n').map(|i| start + i).unwrap_or(text.len());
+    let line = &text[line_start..line_end];
+    let column = text[line_start..start].chars().count();
+    let width = text[start..end.max(start)].chars().count().max(1);
+
+    let mut out = String::new();
+
+    out.push_str(&format!("error: {}\n", message));
+    out.push_str(&format!("  | {}\n", line));
+    let underline: String = repeat(' ').take(column).chain(repeat('^').take(width)).collect();
+    out.push_str(&format!("  | {}\n", underline));
+
+    if let Some(help) = help {
+        out.push_str(&format!("  = {}\n", help));
+    }
+
+    out
+}
@@ -0,0 +1,295 @@
+//! Lexical environments
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num::bigint::BigInt;
+use num::rational::BigRational;
+
+use eval::{Function, Value};
+use util::interner::Name;
+
+/// A single mutable lexical scope
+#[derive(Clone, Debug)]
+pub struct Env(Rc<RefCell<HashMap<Name, Value>>>);
+
+impl Env {
+    /// Creates a fresh, empty scope
+    pub fn new() -> Env {
+        Env(Rc::new(RefCell::new(HashMap::new())))
+    }
+
+    /// Looks `name` up in this frame, cloning the bound value so the
+    /// borrow of the underlying map doesn't outlive the call -- callers
+    /// never see a reference into a cell that a later `insert` could
+    /// invalidate
+    fn get(&self, name: &Name) -> Option<Value> {
+        self.0.borrow().get(name).cloned()
+    }
+
+    fn insert(&self, name: Name, value: Value) {
+        self.0.borrow_mut().insert(name, value);
+    }
+
+    fn names(&self) -> Vec<Name> {
+        self.0.borrow().keys().cloned().collect()
+    }
+}
+
+/// A chain of lexical scopes, innermost last.
+///
+/// `Stack` is cheap to clone: the clone shares the very same frames as the
+/// original, because each `Env` frame is reference-counted and interior-
+/// mutable via a `RefCell`. This is what lets a closure's `captured: Stack`
+/// (see `eval::Function::Closure`) stay in sync with a later `insert` made
+/// through a different clone of the same stack — in particular, the
+/// reserve-then-overwrite dance for `Operator::Def` in `eval::expr` that makes a
+/// lambda visible inside its own body for recursion, and the trampoline in
+/// `eval::expr`, which clones `Stack` on every step of its loop. `get`
+/// returns an owned, cloned `Value` rather than a borrow out of the cell,
+/// so a caller can hold on to the result across an `insert` without
+/// tripping `RefCell`'s runtime borrow check.
+#[derive(Clone, Debug)]
+pub struct Stack(Vec<Env>);
+
+impl Stack {
+    /// A stack with a single, empty frame
+    pub fn new() -> Stack {
+        Stack(vec![Env::new()])
+    }
+
+    /// Pushes a new, innermost frame, returning the extended stack
+    pub fn push(&self, env: Env) -> Stack {
+        let mut frames = self.0.clone();
+        frames.push(env);
+        Stack(frames)
+    }
+
+    /// Binds `name` in the innermost frame
+    pub fn insert(&self, name: Name, value: Value) {
+        self.0.last().expect("a Stack always has at least one frame").insert(name, value)
+    }
+
+    /// Looks `name` up, innermost frame first
+    pub fn get(&self, name: &Name) -> Option<Value> {
+        for env in self.0.iter().rev() {
+            if let Some(value) = env.get(name) {
+                return Some(value)
+            }
+        }
+
+        None
+    }
+
+    /// Every name currently bound, across all frames; used to build the
+    /// "did you mean" suggestions for `Error_::UndefinedSymbol`
+    pub fn names(&self) -> Vec<Name> {
+        let mut names = Vec::new();
+
+        for env in &self.0 {
+            names.extend(env.names());
+        }
+
+        names
+    }
+}
+
+impl Default for Stack {
+    /// The top-level environment, with the numeric built-ins installed
+    fn default() -> Stack {
+        let stack = Stack::new();
+
+        stack.insert(Name::intern("+"), Value::Function(Function::new(add)));
+        stack.insert(Name::intern("-"), Value::Function(Function::new(sub)));
+        stack.insert(Name::intern("*"), Value::Function(Function::new(mul)));
+        stack.insert(Name::intern("/"), Value::Function(Function::new(div)));
+
+        stack
+    }
+}
+
+/// Adds two numbers, promoting `i64` to `BigInt` on overflow and otherwise
+/// following `Value::promote`'s numeric tower
+fn add2(a: Value, b: Value) -> Option<Value> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a.clone(), b.clone()) {
+        return Some(match x.checked_add(y) {
+            Some(sum) => Value::Integer(sum),
+            None => Value::BigInt(BigInt::from(x) + BigInt::from(y)),
+        })
+    }
+
+    let (a, b) = match a.promote(b) {
+        Some(pair) => pair,
+        None => return None,
+    };
+
+    match (a, b) {
+        (Value::BigInt(x), Value::BigInt(y)) => Some(Value::BigInt(x + y)),
+        (Value::Ratio(x), Value::Ratio(y)) => Some(Value::Ratio(x + y)),
+        (Value::Float(x), Value::Float(y)) => Some(Value::Float(x + y)),
+        (Value::Complex(x), Value::Complex(y)) => Some(Value::Complex(x + y)),
+        _ => None,
+    }
+}
+
+/// Subtracts two numbers, promoting `i64` to `BigInt` on overflow and
+/// otherwise following `Value::promote`'s numeric tower
+fn sub2(a: Value, b: Value) -> Option<Value> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a.clone(), b.clone()) {
+        return Some(match x.checked_sub(y) {
+            Some(diff) => Value::Integer(diff),
+            None => Value::BigInt(BigInt::from(x) - BigInt::from(y)),
+        })
+    }
+
+    let (a, b) = match a.promote(b) {
+        Some(pair) => pair,
+        None => return None,
+    };
+
+    match (a, b) {
+        (Value::BigInt(x), Value::BigInt(y)) => Some(Value::BigInt(x - y)),
+        (Value::Ratio(x), Value::Ratio(y)) => Some(Value::Ratio(x - y)),
+        (Value::Float(x), Value::Float(y)) => Some(Value::Float(x - y)),
+        (Value::Complex(x), Value::Complex(y)) => Some(Value::Complex(x - y)),
+        _ => None,
+    }
+}
+
+/// Multiplies two numbers, promoting `i64` to `BigInt` on overflow and
+/// otherwise following `Value::promote`'s numeric tower
+fn mul2(a: Value, b: Value) -> Option<Value> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a.clone(), b.clone()) {
+        return Some(match x.checked_mul(y) {
+            Some(product) => Value::Integer(product),
+            None => Value::BigInt(BigInt::from(x) * BigInt::from(y)),
+        })
+    }
+
+    let (a, b) = match a.promote(b) {
+        Some(pair) => pair,
+        None => return None,
+    };
+
+    match (a, b) {
+        (Value::BigInt(x), Value::BigInt(y)) => Some(Value::BigInt(x * y)),
+        (Value::Ratio(x), Value::Ratio(y)) => Some(Value::Ratio(x * y)),
+        (Value::Float(x), Value::Float(y)) => Some(Value::Float(x * y)),
+        (Value::Complex(x), Value::Complex(y)) => Some(Value::Complex(x * y)),
+        _ => None,
+    }
+}
+
+/// Divides two numbers. Two integers divide to an exact `Integer`/`BigInt`
+/// when they divide evenly, and to a `Ratio` otherwise. Dividing an
+/// integer, bigint or ratio by zero is an error (`None`); float and complex
+/// division by zero is not checked and yields infinity or NaN
+fn div2(a: Value, b: Value) -> Option<Value> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a.clone(), b.clone()) {
+        if y == 0 {
+            return None
+        }
+
+        if x % y == 0 {
+            return Some(Value::Integer(x / y))
+        }
+
+        return Some(Value::Ratio(BigRational::new(BigInt::from(x), BigInt::from(y))))
+    }
+
+    let (a, b) = match a.promote(b) {
+        Some(pair) => pair,
+        None => return None,
+    };
+
+    match (a, b) {
+        (Value::BigInt(x), Value::BigInt(y)) => {
+            if y == BigInt::from(0) {
+                return None
+            }
+
+            let ratio = BigRational::new(x, y);
+
+            if ratio.is_integer() {
+                Some(Value::BigInt(ratio.to_integer()))
+            } else {
+                Some(Value::Ratio(ratio))
+            }
+        },
+        (Value::Ratio(x), Value::Ratio(y)) => {
+            if y == BigRational::from_integer(BigInt::from(0)) {
+                return None
+            }
+
+            Some(Value::Ratio(x / y))
+        },
+        (Value::Float(x), Value::Float(y)) => Some(Value::Float(x / y)),
+        (Value::Complex(x), Value::Complex(y)) => Some(Value::Complex(x / y)),
+        _ => None,
+    }
+}
+
+fn add(args: &[Value]) -> Option<Value> {
+    let mut acc = Value::Integer(0);
+
+    for arg in args {
+        acc = match add2(acc, arg.clone()) {
+            Some(value) => value,
+            None => return None,
+        };
+    }
+
+    Some(acc)
+}
+
+fn mul(args: &[Value]) -> Option<Value> {
+    let mut acc = Value::Integer(1);
+
+    for arg in args {
+        acc = match mul2(acc, arg.clone()) {
+            Some(value) => value,
+            None => return None,
+        };
+    }
+
+    Some(acc)
+}
+
+fn sub(args: &[Value]) -> Option<Value> {
+    match args {
+        [] => None,
+        [ref x] => sub2(Value::Integer(0), x.clone()),
+        [ref first, rest..] => {
+            let mut acc = first.clone();
+
+            for arg in rest {
+                acc = match sub2(acc, arg.clone()) {
+                    Some(value) => value,
+                    None => return None,
+                };
+            }
+
+            Some(acc)
+        },
+    }
+}
+
+fn div(args: &[Value]) -> Option<Value> {
+    match args {
+        [] => None,
+        [ref x] => div2(Value::Integer(1), x.clone()),
+        [ref first, rest..] => {
+            let mut acc = first.clone();
+
+            for arg in rest {
+                acc = match div2(acc, arg.clone()) {
+                    Some(value) => value,
+                    None => return None,
+                };
+            }
+
+            Some(acc)
+        },
+    }
+}
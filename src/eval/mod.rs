@@ -1,8 +1,11 @@
 //! Evaluation
 
 use std::fmt;
-use std::ops::Deref;
 
+use num::bigint::BigInt;
+use num::complex::Complex64;
+use num::rational::BigRational;
+use num::traits::ToPrimitive;
 use rc::Rc;
 
 use eval::env::{Env, Stack};
@@ -17,33 +20,42 @@ pub type Error = Spanned<Error_>;
 
 /// A built-in function or a user defined lambda
 #[derive(Clone)]
-pub struct Function(Rc<Fn(&[Value]) -> Option<Value>>);
+pub enum Function {
+    /// A function implemented in Rust, installed by `Stack::default()`
+    Builtin(Rc<Fn(&[Value]) -> Option<Value>>),
+    /// A function defined in Lisp with `fn`/`lambda`, together with the
+    /// environment it closed over when it was created
+    Closure {
+        params: Vec<Name>,
+        body: Rc<Expr>,
+        captured: Stack,
+    },
+}
 
 impl Function {
     fn new<F>(f: F) -> Function where F: Fn(&[Value]) -> Option<Value> + 'static {
         let boxed_f: Box<Fn(&[Value]) -> Option<Value>> = Box::new(f);
-        Function(Rc::from(boxed_f))
+        Function::Builtin(Rc::from(boxed_f))
     }
 }
 
 impl fmt::Debug for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use std::mem;
-        use std::raw::TraitObject;
-
-        let TraitObject { data, .. } = unsafe {
-            mem::transmute(self.0.deref())
-        };
-
-        data.fmt(f)
-    }
-}
+        match *self {
+            Function::Builtin(ref function) => {
+                use std::mem;
+                use std::raw::TraitObject;
 
-impl Deref for Function {
-    type Target = Fn(&[Value]) -> Option<Value> + 'static;
+                let TraitObject { data, .. } = unsafe {
+                    mem::transmute(&**function)
+                };
 
-    fn deref(&self) -> &(Fn(&[Value]) -> Option<Value> + 'static) {
-        self.0.deref()
+                data.fmt(f)
+            },
+            Function::Closure { ref params, .. } => {
+                write!(f, "<closure/{}>", params.len())
+            },
+        }
     }
 }
 
@@ -52,21 +64,31 @@ impl Deref for Function {
 pub enum Error_ {
     /// `()`
     EmptyList,
-    /// `(a 1 2)` where `a = 2`
-    ExpectedFunction,
+    /// `(a 1 2)` where `a = 2`; `found` is the actual type of the head value
+    /// so the renderer can report it
+    ExpectedFunction { found: &'static str },
     /// `(1 2 3)`
     ExpectedSymbol,
-    /// `(foo 1 2)`
-    UndefinedSymbol,
+    /// `(foo 1 2)`; `candidates` are the symbols bound in scope at the
+    /// error site, for the renderer to pick the closest match from
+    UndefinedSymbol { name: Name, candidates: Vec<Name> },
     /// `(+ 1)`
     UnsupportedOperation,
+    /// `(f 1 2)` where `f = (fn [a] a)`
+    WrongArity { expected: usize, found: usize },
 }
 
 /// A value
 #[derive(Clone, Debug)]
 pub enum Value {
+    /// `10000000000000000000000000000000`, an integer that overflowed `i64`
+    BigInt(BigInt),
     /// `true` or `false`
     Bool(bool),
+    /// `1+2i`
+    Complex(Complex64),
+    /// `2.5`
+    Float(f64),
     /// `+`
     Function(Function),
     /// `123`
@@ -75,12 +97,24 @@ pub enum Value {
     Keyword(Name),
     ///  `nil`
     Nil,
+    /// `3/4`, an exact fraction
+    Ratio(BigRational),
     /// `"Hello, world!"`
     String(String),
     /// `[1 "two" [3]]`
     Vector(Vec<Value>),
 }
 
+/// The numeric tower, ordered from narrowest to widest representation
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Rank {
+    Integer,
+    BigInt,
+    Ratio,
+    Float,
+    Complex,
+}
+
 impl Value {
     /// Formats this value
     pub fn display(&self, interner: &Interner) -> String {
@@ -93,9 +127,23 @@ impl Value {
         use std::fmt::Write;
 
         match *self {
+            Value::BigInt(ref int) => {
+                write!(string, "{}", int).ok();
+            },
             Value::Bool(bool) => {
                 write!(string, "{}", bool).ok();
             },
+            Value::Complex(ref complex) => {
+                // `{:?}` for the same round-tripping reason as `Float`
+                // below; the sign is written explicitly so a negative `im`
+                // reads as `a-bi` instead of colliding into `a+-bi`
+                let sign = if complex.im < 0. { '-' } else { '+' };
+                write!(string, "{:?}{}{:?}i", complex.re, sign, complex.im.abs()).ok();
+            },
+            Value::Float(float) => {
+                // `{:?}` always prints a decimal point, so `2.0` round-trips
+                write!(string, "{:?}", float).ok();
+            },
             Value::Function(ref function) => {
                 write!(string, "<function at {:?}>", function).ok();
             },
@@ -104,6 +152,9 @@ impl Value {
             },
             Value::Keyword(ref name) => string.push_str(&interner.get(name)),
             Value::Nil => string.push_str("nil"),
+            Value::Ratio(ref ratio) => {
+                write!(string, "{}/{}", ratio.numer(), ratio.denom()).ok();
+            },
             Value::String(ref s) => string.push_str(s),
             Value::Vector(ref elems) => {
                 string.push('[');
@@ -123,10 +174,188 @@ impl Value {
             }
         }
     }
+
+    /// A short, user-facing name for this value's type, used in diagnostics
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::BigInt(_) => "bigint",
+            Value::Bool(_) => "bool",
+            Value::Complex(_) => "complex",
+            Value::Float(_) => "float",
+            Value::Function(_) => "function",
+            Value::Integer(_) => "integer",
+            Value::Keyword(_) => "keyword",
+            Value::Nil => "nil",
+            Value::Ratio(_) => "ratio",
+            Value::String(_) => "string",
+            Value::Vector(_) => "vector",
+        }
+    }
+
+    /// This value's position in the numeric tower, or `None` if it is not a
+    /// number at all
+    fn rank(&self) -> Option<Rank> {
+        match *self {
+            Value::Integer(_) => Some(Rank::Integer),
+            Value::BigInt(_) => Some(Rank::BigInt),
+            Value::Ratio(_) => Some(Rank::Ratio),
+            Value::Float(_) => Some(Rank::Float),
+            Value::Complex(_) => Some(Rank::Complex),
+            _ => None,
+        }
+    }
+
+    /// Casts this number up to `rank`. Only ever widens; `self.rank()` must
+    /// be `<= rank`.
+    fn cast(self, rank: Rank) -> Value {
+        if self.rank() == Some(rank) {
+            return self
+        }
+
+        match (self, rank) {
+            (Value::Integer(i), Rank::BigInt) => Value::BigInt(BigInt::from(i)),
+            (Value::Integer(i), Rank::Ratio) => {
+                Value::Ratio(BigRational::from_integer(BigInt::from(i)))
+            },
+            (Value::Integer(i), Rank::Float) => Value::Float(i as f64),
+            (Value::Integer(i), Rank::Complex) => Value::Complex(Complex64::new(i as f64, 0.)),
+            (Value::BigInt(i), Rank::Ratio) => Value::Ratio(BigRational::from_integer(i)),
+            (Value::BigInt(i), Rank::Float) => Value::Float(i.to_f64().unwrap_or(0.)),
+            (Value::BigInt(i), Rank::Complex) => {
+                Value::Complex(Complex64::new(i.to_f64().unwrap_or(0.), 0.))
+            },
+            (Value::Ratio(r), Rank::Float) => Value::Float(r.to_f64().unwrap_or(0.)),
+            (Value::Ratio(r), Rank::Complex) => {
+                Value::Complex(Complex64::new(r.to_f64().unwrap_or(0.), 0.))
+            },
+            (Value::Float(f), Rank::Complex) => Value::Complex(Complex64::new(f, 0.)),
+            (value, _) => value,
+        }
+    }
+
+    /// Promotes a pair of numbers to their common representation, e.g.
+    /// `(Integer, Float)` becomes `(Float, Float)`. Returns `None` if either
+    /// value is not a number, in which case the arithmetic built-ins should
+    /// report `UnsupportedOperation`.
+    pub fn promote(self, other: Value) -> Option<(Value, Value)> {
+        let (a, b) = match (self.rank(), other.rank()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return None,
+        };
+
+        let rank = if a > b { a } else { b };
+
+        Some((self.cast(rank), other.cast(rank)))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        if self.rank().is_some() && other.rank().is_some() {
+            return match self.clone().promote(other.clone()) {
+                Some((Value::Integer(a), Value::Integer(b))) => a == b,
+                Some((Value::BigInt(a), Value::BigInt(b))) => a == b,
+                Some((Value::Ratio(a), Value::Ratio(b))) => a == b,
+                Some((Value::Float(a), Value::Float(b))) => a == b,
+                Some((Value::Complex(a), Value::Complex(b))) => a == b,
+                _ => unreachable!(),
+            }
+        }
+
+        match (self, other) {
+            (&Value::Bool(a), &Value::Bool(b)) => a == b,
+            (&Value::Keyword(a), &Value::Keyword(b)) => a == b,
+            (&Value::Nil, &Value::Nil) => true,
+            (&Value::String(ref a), &Value::String(ref b)) => a == b,
+            (&Value::Vector(ref a), &Value::Vector(ref b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Expands one step of a `->`/`->>` thread by splicing `acc` into `step` as
+/// the first (`->`) or last (`->>`) argument. A bare symbol step such as
+/// `f` in `(-> x f)` is treated as the call `(f x)`.
+fn thread_step(operator: Operator, acc: Expr, step: &Expr) -> Result<Expr, Error> {
+    let (head, mut args) = match step.node {
+        Expr_::Symbol(_) => (step.clone(), Vec::new()),
+        Expr_::List(ref exprs) => match &exprs[..] {
+            [ref head, tail..] => (head.clone(), tail.to_vec()),
+            [] => return Err(Spanned::new(step.span, Error_::UnsupportedOperation)),
+        },
+        _ => return Err(Spanned::new(step.span, Error_::UnsupportedOperation)),
+    };
+
+    match operator {
+        Operator::ThreadFirst => args.insert(0, acc),
+        Operator::ThreadLast => args.push(acc),
+        _ => unreachable!(),
+    }
+
+    let mut exprs = Vec::with_capacity(args.len() + 1);
+    exprs.push(head);
+    exprs.extend(args);
+
+    Ok(Spanned::new(step.span, Expr_::List(exprs)))
+}
+
+/// Whether a value is truthy, i.e. whether `if` should take its `then`
+/// branch
+fn truthy(value: Value) -> bool {
+    match value {
+        Value::Bool(false) | Value::Nil => false,
+        _ => true,
+    }
+}
+
+/// Whether `expr` is a `(fn ...)` form, i.e. whether it would produce a
+/// `Value::Function(Function::Closure { .. })` if evaluated
+fn is_lambda(expr: &Expr) -> bool {
+    match expr.node {
+        Expr_::List(ref exprs) => match exprs.first() {
+            Some(head) => match head.node {
+                Expr_::Operator(Operator::Lambda) => true,
+                _ => false,
+            },
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// The outcome of evaluating a single step of the trampoline in `expr`
+enum Step {
+    /// A final value; the trampoline stops here
+    Value(Value),
+    /// The next (expression, environment) pair to evaluate in tail
+    /// position; the trampoline loops instead of recursing
+    Tail(Expr, Stack),
 }
 
 /// Evaluates an expression
+///
+/// This is a trampoline: a tail position (the chosen branch of `if`, the
+/// body of `let`, the body of a called closure) is evaluated by looping
+/// instead of recursing, so self/mutually tail-recursive Lisp functions run
+/// in constant Rust stack space. Non-tail subexpressions (arguments,
+/// conditions, binding initializers) still recurse through this function.
 pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Error> {
+    let mut expr = expr.clone();
+    let mut env = env.clone();
+
+    loop {
+        match try!(step(&expr, source, &mut env)) {
+            Step::Value(value) => return Ok(value),
+            Step::Tail(next_expr, next_env) => {
+                expr = next_expr;
+                env = next_env;
+            },
+        }
+    }
+}
+
+/// Evaluates one trampoline step of `expr`
+fn step(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Step, Error> {
     macro_rules! err {
         ($span:expr, $err:ident) => {
             Err(Spanned::new($span.span, Error_::$err))
@@ -134,9 +363,9 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
     }
 
     match expr.node {
-        Expr_::Bool(bool) => Ok(Value::Bool(bool)),
-        Expr_::Integer(integer) => Ok(Value::Integer(integer)),
-        Expr_::Keyword(name) => Ok(Value::Keyword(name)),
+        Expr_::Bool(bool) => Ok(Step::Value(Value::Bool(bool))),
+        Expr_::Integer(integer) => Ok(Step::Value(Value::Integer(integer))),
+        Expr_::Keyword(name) => Ok(Step::Value(Value::Keyword(name))),
         Expr_::Operator(_) => {
             // This is a syntax error that gets caught earlier on
             unreachable!()
@@ -147,13 +376,24 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                 Expr_::Operator(operator) => {
                     match operator {
                         Operator::Def => {
-                            if let [ref symbol, ref expr] = tail {
+                            if let [ref symbol, ref value_expr] = tail {
                                 if let Expr_::Symbol(symbol) = symbol.node {
-                                    let value = try!(::eval::expr(expr, source, env));
+                                    // Only reserve the name ahead of time
+                                    // when the right-hand side is itself a
+                                    // lambda, so that it can refer to
+                                    // itself and recurse. For anything
+                                    // else, binding a placeholder first
+                                    // would leave `symbol` pointing at
+                                    // `nil` if evaluation below fails.
+                                    if is_lambda(value_expr) {
+                                        env.insert(symbol, Value::Nil);
+                                    }
+
+                                    let value = try!(::eval::expr(value_expr, source, env));
 
                                     env.insert(symbol, value.clone());
 
-                                    Ok(value)
+                                    Ok(Step::Value(value))
                                 } else {
                                     err!(symbol, ExpectedSymbol)
                                 }
@@ -163,13 +403,38 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                         },
                         Operator::If => {
                             if let [ref cond, ref then, ref els] = tail {
-                                if match try!(::eval::expr(cond, source, env)) {
-                                    Value::Bool(false) | Value::Nil => false,
-                                    _ => true,
-                                } {
-                                    ::eval::expr(then, source, env)
+                                let branch = if truthy(try!(::eval::expr(cond, source, env))) {
+                                    then
                                 } else {
-                                    ::eval::expr(els, source, env)
+                                    els
+                                };
+
+                                Ok(Step::Tail(branch.clone(), env.clone()))
+                            } else {
+                                err!(expr, UnsupportedOperation)
+                            }
+                        },
+                        Operator::Lambda => {
+                            if let [ref params, ref body] = tail {
+                                match params.node {
+                                    Expr_::List(ref params) | Expr_::Vector(ref params) => {
+                                        let mut names = Vec::with_capacity(params.len());
+
+                                        for param in params {
+                                            if let Expr_::Symbol(name) = param.node {
+                                                names.push(name);
+                                            } else {
+                                                return err!(param, ExpectedSymbol)
+                                            }
+                                        }
+
+                                        Ok(Step::Value(Value::Function(Function::Closure {
+                                            params: names,
+                                            body: Rc::new(body.clone()),
+                                            captured: env.clone(),
+                                        })))
+                                    },
+                                    _ => err!(params, UnsupportedOperation),
                                 }
                             } else {
                                 err!(expr, UnsupportedOperation)
@@ -183,12 +448,13 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                                             return err!(expr, UnsupportedOperation)
                                         }
 
-                                        let ref mut env = env.push(Env::new());
+                                        let mut env = env.push(Env::new());
 
                                         for binding in bindings.chunks(2) {
-                                            if let [ref symbol, ref expr] = binding {
+                                            if let [ref symbol, ref binding_expr] = binding {
                                                 if let Expr_::Symbol(symbol) = symbol.node {
-                                                    let value = ::eval::expr(expr, source, env);
+                                                    let value =
+                                                        ::eval::expr(binding_expr, source, &mut env);
 
                                                     env.insert(symbol, try!(value))
                                                 } else {
@@ -200,7 +466,7 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                                             }
                                         }
 
-                                        ::eval::expr(ret, source, env)
+                                        Ok(Step::Tail(ret.clone(), env))
                                     },
                                     _ => err!(expr, UnsupportedOperation),
 
@@ -209,10 +475,23 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                                 err!(expr, UnsupportedOperation)
                             }
                         },
+                        Operator::ThreadFirst | Operator::ThreadLast => {
+                            if let [ref seed, steps..] = tail {
+                                let mut acc = seed.clone();
+
+                                for thread_step_expr in steps {
+                                    acc = try!(thread_step(operator, acc, thread_step_expr));
+                                }
+
+                                Ok(Step::Tail(acc, env.clone()))
+                            } else {
+                                err!(expr, UnsupportedOperation)
+                            }
+                        },
                     }
                 },
                 Expr_::Symbol(ref symbol) => {
-                    if let Some(value) = env.get(symbol).map(Clone::clone) {
+                    if let Some(value) = env.get(symbol) {
                         match value {
                             Value::Function(function) => {
                                 let mut args = Vec::with_capacity(tail.len());
@@ -221,29 +500,57 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                                     args.push(try!(::eval::expr(elem, source, env)));
                                 }
 
-                                if let Some(value) = function(&args) {
-                                    Ok(value)
-                                } else {
-                                    err!(expr, UnsupportedOperation)
+                                match function {
+                                    Function::Builtin(ref f) => {
+                                        if let Some(value) = f(&args) {
+                                            Ok(Step::Value(value))
+                                        } else {
+                                            err!(expr, UnsupportedOperation)
+                                        }
+                                    },
+                                    Function::Closure { ref params, ref body, ref captured } => {
+                                        if params.len() != args.len() {
+                                            return Err(Spanned::new(expr.span, Error_::WrongArity {
+                                                expected: params.len(),
+                                                found: args.len(),
+                                            }))
+                                        }
+
+                                        let mut call_env = captured.clone().push(Env::new());
+
+                                        for (&param, arg) in params.iter().zip(args) {
+                                            call_env.insert(param, arg);
+                                        }
+
+                                        Ok(Step::Tail((**body).clone(), call_env))
+                                    },
                                 }
                             },
-                            _ => err!(head, ExpectedFunction),
+                            _ => Err(Spanned::new(head.span, Error_::ExpectedFunction {
+                                found: value.type_name(),
+                            })),
 
                         }
                     } else {
-                        err!(head, UndefinedSymbol)
+                        Err(Spanned::new(head.span, Error_::UndefinedSymbol {
+                            name: *symbol,
+                            candidates: env.names(),
+                        }))
                     }
                 },
                 _ => err!(head, ExpectedSymbol)
             },
         },
-        Expr_::Nil => Ok(Value::Nil),
-        Expr_::String => Ok(Value::String(String::from_str(&source[expr.span]))),
+        Expr_::Nil => Ok(Step::Value(Value::Nil)),
+        Expr_::String => Ok(Step::Value(Value::String(String::from_str(&source[expr.span])))),
         Expr_::Symbol(ref symbol) => {
             if let Some(value) = env.get(symbol) {
-                Ok(value.clone())
+                Ok(Step::Value(value.clone()))
             } else {
-                err!(expr, UndefinedSymbol)
+                Err(Spanned::new(expr.span, Error_::UndefinedSymbol {
+                    name: *symbol,
+                    candidates: env.names(),
+                }))
             }
         },
         Expr_::Vector(ref exprs) => {
@@ -253,7 +560,7 @@ pub fn expr(expr: &Expr, source: &Source, env: &mut Stack) -> Result<Value, Erro
                 elems.push(try!(::eval::expr(expr, source, env)))
             }
 
-            Ok(Value::Vector(elems))
+            Ok(Step::Value(Value::Vector(elems)))
         },
     }
 }